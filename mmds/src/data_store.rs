@@ -0,0 +1,188 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use serde_json::Value;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::{events, json_patch};
+
+/// Errors that can occur while interacting with the [`Mmds`] data store.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The requested path does not exist in the store.
+    NotFound,
+    /// The stored document (or a patch applied to it) contains a value type the store
+    /// does not support, e.g. a JSON array.
+    UnsupportedValueType,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NotFound => write!(f, "The requested resource does not exist."),
+            Error::UnsupportedValueType => write!(
+                f,
+                "Cannot retrieve value. The value has an unsupported type."
+            ),
+        }
+    }
+}
+
+/// The MMDS document store: a JSON object tree of scalar leaves, browsable by path.
+#[derive(Clone, Debug, Default)]
+pub struct Mmds {
+    data_store: Value,
+}
+
+impl Mmds {
+    /// Replaces the entire document, rejecting arrays, numbers and booleans anywhere in
+    /// the tree.
+    pub fn put_data(&mut self, data: Value) -> Result<(), Error> {
+        if Mmds::is_valid(&data) {
+            self.data_store = data;
+            events::publish("/", self.data_store.clone());
+            Ok(())
+        } else {
+            Err(Error::UnsupportedValueType)
+        }
+    }
+
+    /// Applies `patch` to the document using JSON Merge Patch semantics.
+    ///
+    /// `json_patch` publishes its own per-path change events as it recurses, so unlike
+    /// [`Mmds::put_data`] there is no separate whole-document event here.
+    pub fn patch_data(&mut self, patch: Value) -> Result<(), Error> {
+        if Mmds::is_valid(&patch) {
+            json_patch(&mut self.data_store, &patch);
+            Ok(())
+        } else {
+            Err(Error::UnsupportedValueType)
+        }
+    }
+
+    // MMDS only stores strings and (nested) objects of strings, mirroring the EC2 metadata
+    // service; `Null` is also accepted since JSON Merge Patch uses it to mark key removal.
+    fn is_valid(value: &Value) -> bool {
+        match value {
+            Value::String(_) | Value::Null => true,
+            Value::Object(map) => map.values().all(Mmds::is_valid),
+            Value::Array(_) | Value::Number(_) | Value::Bool(_) => false,
+        }
+    }
+
+    /// Resolves `path` (e.g. `/name/first`) against the document and returns the raw
+    /// `Value` found there. Callers decide how to render it (JSON, EC2 plaintext, ...).
+    pub fn get_subtree(&self, path: String) -> Result<&Value, Error> {
+        let mut value = &self.data_store;
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            value = match value {
+                Value::Object(map) => map.get(segment).ok_or(Error::NotFound)?,
+                _ => return Err(Error::NotFound),
+            };
+        }
+        Ok(value)
+    }
+
+    /// Atomically writes the document to `path` as JSON (write to a temp file, then
+    /// rename over the destination, so a crash mid-write never leaves a truncated file).
+    pub fn save_to_path(&self, path: &Path) -> Result<(), PersistenceError> {
+        let tmp_path = path.with_extension("tmp");
+        let serialized =
+            serde_json::to_vec(&self.data_store).expect("a validated MMDS document always serializes");
+        fs::write(&tmp_path, serialized)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Loads and validates a document previously written by [`Mmds::save_to_path`].
+    pub fn load_from_path(path: &Path) -> Result<Mmds, PersistenceError> {
+        let contents = fs::read(path)?;
+        let data_store: Value =
+            serde_json::from_slice(&contents).map_err(|_| PersistenceError::Invalid)?;
+
+        if !Mmds::is_valid(&data_store) {
+            return Err(PersistenceError::Invalid);
+        }
+
+        Ok(Mmds { data_store })
+    }
+}
+
+/// Errors that can occur while saving or restoring an MMDS snapshot file.
+#[derive(Debug)]
+pub enum PersistenceError {
+    Io(io::Error),
+    /// The file is not valid JSON, or does not hold a valid MMDS document.
+    Invalid,
+}
+
+impl PersistenceError {
+    /// `true` if this error is simply "the snapshot file doesn't exist yet".
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, PersistenceError::Io(e) if e.kind() == io::ErrorKind::NotFound)
+    }
+}
+
+impl fmt::Display for PersistenceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PersistenceError::Io(e) => write!(f, "I/O error accessing MMDS snapshot: {}", e),
+            PersistenceError::Invalid => write!(f, "MMDS snapshot file is corrupt or invalid"),
+        }
+    }
+}
+
+impl From<io::Error> for PersistenceError {
+    fn from(e: io::Error) -> Self {
+        PersistenceError::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn snapshot_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("warp-mds-test-{}-{}.json", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_load_from_missing_path_is_not_found() {
+        let path = snapshot_path("missing");
+        let err = Mmds::load_from_path(&path).unwrap_err();
+        assert!(err.is_not_found());
+    }
+
+    #[test]
+    fn test_save_and_load_roundtrip() {
+        let path = snapshot_path("roundtrip");
+        let mut mmds = Mmds::default();
+        mmds.put_data(serde_json::json!({"name": "John"})).unwrap();
+        mmds.save_to_path(&path).unwrap();
+
+        let loaded = Mmds::load_from_path(&path).unwrap();
+        assert_eq!(
+            loaded.get_subtree("/name".to_string()).unwrap(),
+            &Value::String("John".to_string())
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_rejects_invalid_document() {
+        let path = snapshot_path("invalid");
+        fs::write(&path, b"[1, 2, 3]").unwrap();
+
+        assert!(matches!(
+            Mmds::load_from_path(&path),
+            Err(PersistenceError::Invalid)
+        ));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,54 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Broadcast channel notifying subscribers of `/mds/events` about document mutations.
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::broadcast;
+
+// Bounds how far a slow subscriber may lag before it starts missing events; past this
+// many unconsumed events it gets a `BroadcastStreamRecvError::Lagged` on next poll.
+const CHANNEL_CAPACITY: usize = 128;
+
+/// A single metadata mutation, as broadcast to `/mds/events` subscribers.
+#[derive(Clone, Debug, Serialize)]
+pub struct MdsEvent {
+    pub path: String,
+    pub value: Value,
+}
+
+lazy_static! {
+    static ref EVENTS: broadcast::Sender<MdsEvent> = broadcast::channel(CHANNEL_CAPACITY).0;
+}
+
+/// Publishes a mutation event. A no-op when there are currently no subscribers.
+pub fn publish(path: impl Into<String>, value: Value) {
+    // `send` only errors when there are no receivers, which just means nobody is
+    // watching right now - not a failure worth surfacing to the caller.
+    let _ = EVENTS.send(MdsEvent {
+        path: path.into(),
+        value,
+    });
+}
+
+/// Subscribes to future mutation events.
+pub fn subscribe() -> broadcast::Receiver<MdsEvent> {
+    EVENTS.subscribe()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn publish_reaches_subscriber() {
+        let mut receiver = subscribe();
+        publish("/name", Value::String("John".to_string()));
+
+        let event = receiver.recv().await.unwrap();
+        assert_eq!(event.path, "/name");
+        assert_eq!(event.value, Value::String("John".to_string()));
+    }
+}
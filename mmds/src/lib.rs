@@ -4,6 +4,10 @@
 use lazy_static::lazy_static;
 
 pub mod data_store;
+pub mod events;
+pub mod persistence;
+pub mod registry;
+pub mod token;
 
 use serde_json::{Map, Value};
 use std::sync::{Arc, Mutex};
@@ -19,7 +23,15 @@ lazy_static! {
 
 /// Patch provided JSON document (given as `serde_json::Value`) in-place with JSON Merge Patch
 /// [RFC 7396](https://tools.ietf.org/html/rfc7396).
+///
+/// Publishes an `mds/events` notification for every path the patch actually touches (a leaf
+/// assignment or a key removal), rather than one event for the whole document, so subscribers
+/// can react to the specific configuration that changed.
 pub fn json_patch(target: &mut Value, patch: &Value) {
+    json_patch_at("", target, patch);
+}
+
+fn json_patch_at(path: &str, target: &mut Value, patch: &Value) {
     if patch.is_object() {
         if !target.is_object() {
             // Replace target with a serde_json object so we can recursively copy patch values.
@@ -29,49 +41,213 @@ pub fn json_patch(target: &mut Value, patch: &Value) {
         // This is safe since we make sure patch and target are objects beforehand.
         let doc = target.as_object_mut().unwrap();
         for (key, value) in patch.as_object().unwrap() {
+            let child_path = format!("{}/{}", path, key);
             if value.is_null() {
                 // If the value in the patch is null we remove the entry.
-                doc.remove(key.as_str());
+                if doc.remove(key.as_str()).is_some() {
+                    events::publish(child_path, Value::Null);
+                }
             } else {
                 // Recursive call to update target document.
                 // If `key` is not in the target document (it's a new field defined in `patch`)
                 // insert a null placeholder and pass it as the new target
                 // so we can insert new values recursively.
-                json_patch(doc.entry(key.as_str()).or_insert(Value::Null), value);
+                json_patch_at(
+                    &child_path,
+                    doc.entry(key.as_str()).or_insert(Value::Null),
+                    value,
+                );
             }
         }
     } else {
         *target = patch.clone();
+        let path = if path.is_empty() { "/" } else { path };
+        events::publish(path.to_string(), target.clone());
     }
 }
 
 pub mod filters {
+    use super::registry::Registry;
     use super::*;
     use warp::Filter;
 
-    pub fn get_mds() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    pub fn get_mds(
+        store: Arc<Mutex<Mmds>>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         warp::path("mds")
+            .and(authenticate())
             .and(warp::get())
-            .and(warp::path::full())
+            .and(warp::path::tail())
+            .and(warp::header::optional::<String>("accept"))
+            .and(with_store(store))
             .and_then(handlers::get_mds)
     }
 
-    pub fn put_mds() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    pub fn put_mds(
+        store: Arc<Mutex<Mmds>>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         warp::path("mds")
+            .and(authenticate())
             .and(warp::path::end())
             .and(warp::put())
             .and(json_body())
+            .and(with_store(store))
             .and_then(handlers::put_mds)
     }
 
-    pub fn patch_mds() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    pub fn patch_mds(
+        store: Arc<Mutex<Mmds>>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         warp::path("mds")
+            .and(authenticate())
             .and(warp::path::end())
             .and(warp::patch())
             .and(json_body())
+            .and(with_store(store))
             .and_then(handlers::patch_mds)
     }
 
+    /// GET `/mds/events`: subscribes to a live Server-Sent Events stream of document
+    /// mutations. Must come before [`get_mds`] in route ordering since that filter
+    /// otherwise swallows every path under `/mds`. The event bus is process-wide rather
+    /// than per-store, so tenants currently share one event stream.
+    pub fn get_events() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        warp::path!("mds" / "events")
+            .and(authenticate())
+            .and(warp::get())
+            .and_then(handlers::get_events)
+    }
+
+    /// Composes every `/mds` and token-auth route for the single `store` passed in,
+    /// without translating rejections into responses yet. Exposed separately from
+    /// [`api`] so callers (e.g. `main`) can box it and layer serve-time middleware
+    /// before applying `.recover(...)` themselves.
+    pub fn routes(
+        store: Arc<Mutex<Mmds>>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        get_events()
+            .or(get_mds(store.clone()))
+            .or(put_mds(store.clone()))
+            .or(patch_mds(store))
+            .or(put_token())
+    }
+
+    /// [`routes`] with rejections (including [`handlers::Unauthorized`]) translated into
+    /// HTTP responses. What most single-tenant callers want.
+    pub fn api(
+        store: Arc<Mutex<Mmds>>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = std::convert::Infallible> + Clone {
+        routes(store).recover(handlers::handle_rejection)
+    }
+
+    /// Composes the per-tenant equivalents of [`routes`] under `/tenants/:tenant/mds/...`
+    /// (deliberately not `/mds/:tenant/...`, which would collide with the existing flat
+    /// `/mds` routes that already consume everything under that prefix via
+    /// `warp::path::tail()`), resolving (and lazily creating) each tenant's isolated
+    /// [`Mmds`] document from `registry`. Lets one server process host multiple
+    /// tenants/microVMs side by side, each independently PUT/PATCH/GET-able, without the
+    /// lock-poisoning blast radius of a single shared global store. Note that durable
+    /// persistence (see [`persistence`]) is currently scoped to the global [`MMDS`] store
+    /// only - tenant documents are in-memory for now.
+    pub fn tenant_routes(
+        registry: Registry,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        tenant_get_events()
+            .or(tenant_get_mds(registry.clone()))
+            .or(tenant_put_mds(registry.clone()))
+            .or(tenant_patch_mds(registry))
+    }
+
+    /// [`tenant_routes`] with rejections translated into HTTP responses.
+    pub fn tenant_api(
+        registry: Registry,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = std::convert::Infallible> + Clone {
+        tenant_routes(registry).recover(handlers::handle_rejection)
+    }
+
+    fn tenant_get_mds(
+        registry: Registry,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("tenants" / String / "mds" / ..)
+            .and(authenticate())
+            .and(warp::get())
+            .and(warp::path::tail())
+            .and(warp::header::optional::<String>("accept"))
+            .and_then(move |tenant: String, tail: warp::filters::path::Tail, accept: Option<String>| {
+                handlers::get_mds(tail, accept, registry.get_or_create(&tenant))
+            })
+    }
+
+    fn tenant_put_mds(
+        registry: Registry,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("tenants" / String / "mds")
+            .and(authenticate())
+            .and(warp::put())
+            .and(json_body())
+            .and_then(move |tenant: String, data: Value| {
+                handlers::put_mds(data, registry.get_or_create(&tenant))
+            })
+    }
+
+    fn tenant_patch_mds(
+        registry: Registry,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("tenants" / String / "mds")
+            .and(authenticate())
+            .and(warp::patch())
+            .and(json_body())
+            .and_then(move |tenant: String, patch: Value| {
+                handlers::patch_mds(patch, registry.get_or_create(&tenant))
+            })
+    }
+
+    /// Shares the same process-wide event bus as [`get_events`]; kept under the
+    /// tenant-scoped prefix purely for URL symmetry with the other tenant routes.
+    fn tenant_get_events() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        warp::path!("tenants" / String / "mds" / "events")
+            .and(authenticate())
+            .and(warp::get())
+            .and_then(|_tenant: String| handlers::get_events())
+    }
+
+    /// PUT `/latest/api/token`: mints a new IMDSv2-style session token.
+    ///
+    /// The requested TTL is read from the `X-metadata-token-ttl-seconds` header.
+    pub fn put_token() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        warp::path!("latest" / "api" / "token")
+            .and(warp::put())
+            .and(warp::header::<u32>("x-metadata-token-ttl-seconds"))
+            .and_then(handlers::put_token)
+    }
+
+    /// Rejects the request with `401 Unauthorized` when v2 enforcement is on and the
+    /// `X-metadata-token` header is missing, unknown or expired. A no-op otherwise.
+    fn authenticate() -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+        warp::header::optional::<String>("x-metadata-token")
+            .and_then(|token: Option<String>| async move {
+                if !token::is_enforced() {
+                    return Ok(());
+                }
+
+                match token {
+                    Some(token) if token::is_valid(&token) => Ok(()),
+                    _ => Err(warp::reject::custom(handlers::Unauthorized)),
+                }
+            })
+            .untuple_one()
+    }
+
+    /// Injects a fixed, pre-resolved store into the filter chain.
+    fn with_store(
+        store: Arc<Mutex<Mmds>>,
+    ) -> impl Filter<Extract = (Arc<Mutex<Mmds>>,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || store.clone())
+    }
+
     fn json_body() -> impl Filter<Extract = (Value,), Error = warp::Rejection> + Clone {
         warp::body::content_length_limit(10240).and(warp::body::json())
     }
@@ -80,25 +256,36 @@ pub mod filters {
 pub mod handlers {
     use super::*;
     use std::convert::Infallible;
+    use warp::filters::path::Tail;
     use warp::http::{Response, StatusCode};
-    use warp::filters::path::FullPath;
 
-    pub async fn get_mds(fpath: FullPath) -> Result<impl warp::Reply, Infallible> {
-        let path = fpath.as_str().splitn(2, "/mds").collect::<Vec<&str>>()[1]; 
-        let result = MMDS
+    pub async fn get_mds(
+        tail: Tail,
+        accept: Option<String>,
+        store: Arc<Mutex<Mmds>>,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let mmds = store
             .lock()
-            .expect("Failed to build MMDS response due to poisoned lock")
-            .get_value(path.to_string());
+            .expect("Failed to build MMDS response due to poisoned lock");
+        let result = mmds.get_subtree(tail.as_str().to_string());
 
         let response = match result {
-            Ok(value) => Response::builder()
-                .status(StatusCode::OK)
-                .body(serde_json::to_string(&value.join("\n")).unwrap()),
+            Ok(value) => {
+                if wants_json(accept.as_deref()) {
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .body(serde_json::to_string(value).unwrap())
+                } else {
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .body(imds_plaintext(value))
+                }
+            }
 
             Err(e) => match e {
                 MmdsError::NotFound => Response::builder()
                     .status(StatusCode::NOT_FOUND)
-                    .body(format!("{}", e)), 
+                    .body(format!("{}", e)),
                 MmdsError::UnsupportedValueType => Response::builder()
                     .status(StatusCode::INTERNAL_SERVER_ERROR)
                     .body(format!("{}", e)),
@@ -108,12 +295,46 @@ pub mod handlers {
         Ok(response)
     }
 
-    pub async fn put_mds(data: Value) -> Result<impl warp::Reply, Infallible> {
-        let result = MMDS
+    /// `true` when the client explicitly asked for `application/json`. Any other (or
+    /// missing) `Accept` header falls back to the EC2 plaintext directory format.
+    fn wants_json(accept: Option<&str>) -> bool {
+        accept
+            .map(|accept| accept.contains("application/json"))
+            .unwrap_or(false)
+    }
+
+    /// Renders a resolved subtree the way the EC2 metadata service does: a newline
+    /// separated directory listing (with a trailing `/` on sub-object keys) for an
+    /// object node, or the raw, unquoted scalar for a leaf.
+    fn imds_plaintext(value: &Value) -> String {
+        match value {
+            Value::Object(map) => map
+                .iter()
+                .map(|(key, value)| {
+                    if value.is_object() {
+                        format!("{}/", key)
+                    } else {
+                        key.clone()
+                    }
+                })
+                .collect::<Vec<String>>()
+                .join("\n"),
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        }
+    }
+
+    pub async fn put_mds(
+        data: Value,
+        store: Arc<Mutex<Mmds>>,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let result = store
             .lock()
             .expect("Failed to build MMDS response due to poisoned lock")
             .put_data(data);
 
+        mark_dirty_if_global(&result, &store);
+
         let response = match result {
             Ok(()) => Response::builder()
                 .status(StatusCode::NO_CONTENT)
@@ -127,12 +348,17 @@ pub mod handlers {
         Ok(response)
     }
 
-    pub async fn patch_mds(patch: Value) -> Result<impl warp::Reply, Infallible> {
-        let result = MMDS
+    pub async fn patch_mds(
+        patch: Value,
+        store: Arc<Mutex<Mmds>>,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let result = store
             .lock()
             .expect("Failed to build MMDS response due to poisoned lock")
             .patch_data(patch);
 
+        mark_dirty_if_global(&result, &store);
+
         let response = match result {
             Ok(()) => Response::builder()
                 .status(StatusCode::NO_CONTENT)
@@ -145,6 +371,78 @@ pub mod handlers {
 
         Ok(response)
     }
+
+    /// Snapshotting only ever saves the global [`MMDS`] store, so only mark it dirty when
+    /// that's the store a mutation actually landed in - otherwise a write to a tenant store
+    /// would wake the snapshot task to persist (and debounce behind) the wrong document,
+    /// while the tenant's own data is never saved.
+    fn mark_dirty_if_global(result: &Result<(), MmdsError>, store: &Arc<Mutex<Mmds>>) {
+        if result.is_ok() && Arc::ptr_eq(store, &MMDS) {
+            persistence::mark_dirty();
+        }
+    }
+
+    /// Streams document mutations as Server-Sent Events for as long as the client stays
+    /// connected. A lagging subscriber gets a `resync` event instead of being dropped.
+    pub async fn get_events() -> Result<impl warp::Reply, Infallible> {
+        use futures::StreamExt;
+        use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+        use tokio_stream::wrappers::BroadcastStream;
+
+        let stream = BroadcastStream::new(events::subscribe()).map(|item| {
+            let event = match item {
+                Ok(event) => warp::sse::Event::default()
+                    .json_data(&event)
+                    .unwrap_or_else(|_| warp::sse::Event::default().data("")),
+                Err(BroadcastStreamRecvError::Lagged(skipped)) => warp::sse::Event::default()
+                    .event("resync")
+                    .data(format!("missed {} events, refetch /mds", skipped)),
+            };
+            Ok::<_, Infallible>(event)
+        });
+
+        Ok(warp::sse::reply(warp::sse::keep_alive().stream(stream)))
+    }
+
+    pub async fn put_token(ttl_seconds: u32) -> Result<impl warp::Reply, Infallible> {
+        let response = match token::generate_token(ttl_seconds) {
+            Ok(token) => Response::builder()
+                .status(StatusCode::OK)
+                .header("content-type", "text/plain")
+                .body(token),
+            Err(e) => Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .header("content-type", "text/plain")
+                .body(format!("{}", e)),
+        };
+
+        Ok(response)
+    }
+
+    /// Marker rejection produced by the token-auth filter when a request lacks a valid
+    /// `X-metadata-token`. Turned into a `401` response by [`handle_rejection`].
+    #[derive(Debug)]
+    pub struct Unauthorized;
+
+    impl warp::reject::Reject for Unauthorized {}
+
+    /// Top-level rejection handler; wire up with `.recover(handlers::handle_rejection)` when
+    /// composing the full API filter so that [`Unauthorized`] becomes a `401` response.
+    pub async fn handle_rejection(
+        err: warp::Rejection,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let response = if err.find::<Unauthorized>().is_some() {
+            Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .body("Unauthorized".to_string())
+        } else {
+            Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body("Not Found".to_string())
+        };
+
+        Ok(response)
+    }
 }
 
 #[cfg(test)]
@@ -247,11 +545,13 @@ mod tests {
 
     #[tokio::test]
     async fn put_patch_get_ok() {
+        let store = Arc::new(Mutex::new(Mmds::default()));
+
         let resp = request()
             .method("PUT")
             .path("/mds")
             .body(r#"{"c0":{"c1":"12345","c2":"6789"}}"#)
-            .reply(&filters::put_mds())
+            .reply(&filters::put_mds(store.clone()))
             .await;
         assert_eq!(resp.status(), StatusCode::NO_CONTENT);
 
@@ -259,16 +559,80 @@ mod tests {
             .method("PATCH")
             .path("/mds")
             .body(r#"{"c0":{"c3":"67890"}}"#)
-            .reply(&filters::patch_mds())
+            .reply(&filters::patch_mds(store.clone()))
             .await;
         assert_eq!(resp.status(), StatusCode::NO_CONTENT);
 
         let resp = request()
             .method("GET")
             .path("/mds/c0/c3")
-            .reply(&filters::get_mds())
+            .reply(&filters::get_mds(store))
             .await;
         assert_eq!(resp.status(), StatusCode::OK);
-        assert_eq!(resp.body(), r#""67890""#);
+        assert_eq!(resp.body(), "67890");
+    }
+
+    #[tokio::test]
+    async fn get_mds_content_negotiation() {
+        let store = Arc::new(Mutex::new(Mmds::default()));
+
+        request()
+            .method("PUT")
+            .path("/mds")
+            .body(r#"{"c0":{"c1":"12345","c2":"6789"}}"#)
+            .reply(&filters::put_mds(store.clone()))
+            .await;
+
+        // No Accept header: EC2 plaintext directory listing for an object node.
+        let resp = request()
+            .method("GET")
+            .path("/mds/c0")
+            .reply(&filters::get_mds(store.clone()))
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.body(), "c1\nc2");
+
+        // Accept: application/json returns the resolved subtree as real JSON.
+        let resp = request()
+            .method("GET")
+            .path("/mds/c0")
+            .header("accept", "application/json")
+            .reply(&filters::get_mds(store))
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.body(), r#"{"c1":"12345","c2":"6789"}"#);
+    }
+
+    #[tokio::test]
+    async fn token_auth_enforcement() {
+        let store = Arc::new(Mutex::new(Mmds::default()));
+
+        let resp = request()
+            .method("PUT")
+            .path("/latest/api/token")
+            .header("x-metadata-token-ttl-seconds", "60")
+            .reply(&filters::put_token())
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let token = String::from_utf8(resp.body().to_vec()).unwrap();
+
+        token::set_enforced(true);
+
+        let resp = request()
+            .method("GET")
+            .path("/mds/c0")
+            .reply(&filters::api(store.clone()))
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = request()
+            .method("GET")
+            .path("/mds/c0")
+            .header("x-metadata-token", token)
+            .reply(&filters::api(store))
+            .await;
+        assert_ne!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        token::set_enforced(false);
     }
 }
@@ -0,0 +1,70 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Periodic and mutation-triggered persistence of the MMDS document to a snapshot file.
+
+use lazy_static::lazy_static;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::sleep;
+
+use super::data_store::Mmds;
+use super::MMDS;
+
+// Coalesces bursts of mutations (e.g. a PUT immediately followed by several PATCHes)
+// instead of saving after every one of them: the first signal starts this window, and
+// all signals received while it runs fold into a single follow-up snapshot.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+lazy_static! {
+    static ref DIRTY: Notify = Notify::new();
+}
+
+/// Signals the background snapshot task that the document changed. Call after every
+/// successful mutation; the actual write happens debounced, off the request path.
+pub fn mark_dirty() {
+    DIRTY.notify_one();
+}
+
+/// Loads `path` into the global [`MMDS`] store.
+///
+/// A missing file leaves the (already empty) default store in place. A file that exists
+/// but fails to parse or validate is logged and left alone, so a corrupt snapshot never
+/// overwrites good in-memory state.
+pub fn restore_from_path(path: &Path) {
+    match Mmds::load_from_path(path) {
+        Ok(mmds) => {
+            *MMDS
+                .lock()
+                .expect("Failed to access MMDS due to poisoned lock") = mmds;
+        }
+        Err(e) if e.is_not_found() => (),
+        Err(e) => eprintln!(
+            "Refusing to load MMDS snapshot from {}: {}",
+            path.display(),
+            e
+        ),
+    }
+}
+
+/// Spawns a background task that snapshots the store to `path` every `interval`, and
+/// again a short debounce window after any mutation signalled via [`mark_dirty`].
+pub fn spawn_snapshot_task(path: PathBuf, interval: Duration) {
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = sleep(interval) => (),
+                _ = DIRTY.notified() => sleep(DEBOUNCE).await,
+            }
+
+            let snapshot = MMDS
+                .lock()
+                .expect("Failed to access MMDS due to poisoned lock")
+                .clone();
+            if let Err(e) = snapshot.save_to_path(&path) {
+                eprintln!("Failed to save MMDS snapshot to {}: {}", path.display(), e);
+            }
+        }
+    });
+}
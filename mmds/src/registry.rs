@@ -0,0 +1,69 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Per-tenant registry of isolated [`Mmds`] documents, letting a single server process
+//! host multiple tenants/microVMs side by side under `/tenants/:tenant/mds/...`.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use super::data_store::Mmds;
+
+/// A lazily-populated map from tenant name to that tenant's own `Arc<Mutex<Mmds>>`.
+///
+/// Cheap to clone: the underlying map is shared behind an `Arc<Mutex<_>>`, so every
+/// clone observes the same set of tenants.
+#[derive(Clone, Default)]
+pub struct Registry {
+    tenants: Arc<Mutex<HashMap<String, Arc<Mutex<Mmds>>>>>,
+}
+
+impl Registry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the store for `tenant`, creating an empty one on first access.
+    pub fn get_or_create(&self, tenant: &str) -> Arc<Mutex<Mmds>> {
+        let mut tenants = self
+            .tenants
+            .lock()
+            .expect("Failed to access tenant registry due to poisoned lock");
+
+        tenants
+            .entry(tenant.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(Mmds::default())))
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_or_create_is_isolated_per_tenant() {
+        let registry = Registry::new();
+
+        let a = registry.get_or_create("a");
+        a.lock()
+            .unwrap()
+            .put_data(serde_json::json!({"name": "Alice"}))
+            .unwrap();
+
+        let b = registry.get_or_create("b");
+        assert!(b.lock().unwrap().get_subtree("/name".to_string()).is_err());
+
+        // Re-fetching "a" returns the same store, not a fresh one.
+        let a_again = registry.get_or_create("a");
+        assert_eq!(
+            a_again
+                .lock()
+                .unwrap()
+                .get_subtree("/name".to_string())
+                .unwrap(),
+            &serde_json::Value::String("Alice".to_string())
+        );
+    }
+}
@@ -0,0 +1,123 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Session-token store backing IMDSv2-style authenticated metadata access.
+//!
+//! A client first requests a token with a PUT on `/latest/api/token`, then presents that
+//! token as the `X-metadata-token` header on every subsequent request. Enforcement is
+//! opt-in: the store always accepts token requests, but `/mds` endpoints only reject
+//! unauthenticated callers once [`set_enforced`] has been called.
+
+use lazy_static::lazy_static;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Minimum time to live accepted for a session token, in seconds.
+pub const MIN_TOKEN_TTL_SECONDS: u32 = 1;
+/// Maximum time to live accepted for a session token, in seconds.
+pub const MAX_TOKEN_TTL_SECONDS: u32 = 21_600;
+
+const TOKEN_LENGTH: usize = 32;
+
+lazy_static! {
+    // Opaque session token -> expiry instant. Expired entries are pruned lazily on lookup.
+    static ref TOKENS: Mutex<HashMap<String, Instant>> = Mutex::new(HashMap::new());
+}
+
+static ENFORCED: AtomicBool = AtomicBool::new(false);
+
+/// Turns v2 (token-required) enforcement on or off for the `/mds` endpoints.
+pub fn set_enforced(enforced: bool) {
+    ENFORCED.store(enforced, Ordering::Relaxed);
+}
+
+/// Returns whether callers must currently present a valid token to reach `/mds`.
+pub fn is_enforced() -> bool {
+    ENFORCED.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct InvalidTtlError(pub u32);
+
+impl fmt::Display for InvalidTtlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Invalid time to live value provided for token: {}. Please provide a value between {} and {}.",
+            self.0, MIN_TOKEN_TTL_SECONDS, MAX_TOKEN_TTL_SECONDS
+        )
+    }
+}
+
+/// Generates a new opaque session token with the given TTL (in seconds) and stores it.
+///
+/// Returns the token, or an error if `ttl_seconds` falls outside
+/// `[MIN_TOKEN_TTL_SECONDS, MAX_TOKEN_TTL_SECONDS]`.
+pub fn generate_token(ttl_seconds: u32) -> Result<String, InvalidTtlError> {
+    if ttl_seconds < MIN_TOKEN_TTL_SECONDS || ttl_seconds > MAX_TOKEN_TTL_SECONDS {
+        return Err(InvalidTtlError(ttl_seconds));
+    }
+
+    let token: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect();
+
+    let expiry = Instant::now() + Duration::from_secs(u64::from(ttl_seconds));
+    TOKENS
+        .lock()
+        .expect("Failed to access token store due to poisoned lock")
+        .insert(token.clone(), expiry);
+
+    Ok(token)
+}
+
+/// Returns `true` if `token` exists and has not yet expired.
+///
+/// Expired entries encountered during the lookup are pruned from the store.
+pub fn is_valid(token: &str) -> bool {
+    let mut tokens = TOKENS
+        .lock()
+        .expect("Failed to access token store due to poisoned lock");
+
+    let now = Instant::now();
+    tokens.retain(|_, expiry| *expiry > now);
+
+    tokens.contains_key(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_token_rejects_out_of_range_ttl() {
+        assert_eq!(generate_token(0), Err(InvalidTtlError(0)));
+        assert_eq!(
+            generate_token(MAX_TOKEN_TTL_SECONDS + 1),
+            Err(InvalidTtlError(MAX_TOKEN_TTL_SECONDS + 1))
+        );
+    }
+
+    #[test]
+    fn test_generate_and_validate_token() {
+        let token = generate_token(60).unwrap();
+        assert_eq!(token.len(), TOKEN_LENGTH);
+        assert!(is_valid(&token));
+        assert!(!is_valid("not-a-real-token"));
+    }
+
+    #[test]
+    fn test_expired_token_is_pruned() {
+        let token = generate_token(MIN_TOKEN_TTL_SECONDS).unwrap();
+        std::thread::sleep(Duration::from_secs(2));
+        assert!(!is_valid(&token));
+        assert!(!TOKENS.lock().unwrap().contains_key(&token));
+    }
+}
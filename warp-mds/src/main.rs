@@ -1,19 +1,112 @@
+use warp::Filter;
+
+/// The response compression scheme to negotiate, if any.
+#[derive(Clone, Copy)]
+enum Compression {
+    Gzip,
+    Deflate,
+}
+
+/// Serve-time HTTP middleware, off by default so minimal deployments pay nothing for it.
+#[derive(Default)]
+struct ServeOptions {
+    compression: Option<Compression>,
+    cors_origins: Vec<String>,
+}
+
+/// Wraps `routes` with compression and/or CORS according to `options`, boxing the
+/// result since each combination of `.with(...)` calls produces a distinct filter type.
+fn with_middleware<F>(
+    routes: F,
+    options: &ServeOptions,
+) -> warp::filters::BoxedFilter<(impl warp::Reply,)>
+where
+    F: warp::Filter<Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: warp::Reply,
+{
+    let mut routes = routes.boxed();
+
+    // Gzip and deflate are negotiated alternatives, not stackable - applying both would
+    // double-compress the body and leave `Content-Encoding` describing only the last one.
+    match options.compression {
+        Some(Compression::Gzip) => routes = routes.with(warp::compression::gzip()).boxed(),
+        Some(Compression::Deflate) => routes = routes.with(warp::compression::deflate()).boxed(),
+        None => (),
+    }
+    if !options.cors_origins.is_empty() {
+        let cors = warp::cors()
+            .allow_origins(options.cors_origins.iter().map(String::as_str))
+            .allow_methods(vec!["GET", "PUT", "PATCH"])
+            .allow_header("x-metadata-token")
+            .build();
+        routes = routes.with(cors).boxed();
+    }
+
+    routes
+}
+
 #[tokio::main]
 async fn main() {
-    use mmds::filters::{get_mds, patch_mds, put_mds};
+    use mmds::filters::{routes, tenant_routes};
+    use mmds::handlers::handle_rejection;
+    use mmds::registry::Registry;
+    use mmds::{persistence, token, MMDS};
     use std::env::args;
     use std::net::SocketAddr;
-    use warp::Filter;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    const DEFAULT_SNAPSHOT_INTERVAL_SECS: u64 = 60;
+
+    let mut addr = "127.0.0.1:8080".to_string();
+    let mut snapshot_path: Option<PathBuf> = None;
+    let mut snapshot_interval_secs = DEFAULT_SNAPSHOT_INTERVAL_SECS;
+    let mut options = ServeOptions::default();
+    let mut positionals = Vec::new();
+
+    let mut remaining = args().skip(1);
+    while let Some(arg) = remaining.next() {
+        match arg.as_str() {
+            "--enforce-token-auth" => token::set_enforced(true),
+            "--snapshot-interval-secs" => {
+                if let Some(secs) = remaining.next().and_then(|secs| secs.parse().ok()) {
+                    snapshot_interval_secs = secs;
+                }
+            }
+            "--compression" => {
+                options.compression = match remaining.next().as_deref() {
+                    Some("gzip") => Some(Compression::Gzip),
+                    Some("deflate") => Some(Compression::Deflate),
+                    _ => None,
+                };
+            }
+            "--cors-origin" => {
+                if let Some(origin) = remaining.next() {
+                    options.cors_origins.push(origin);
+                }
+            }
+            _ => positionals.push(arg),
+        }
+    }
 
-    let api = get_mds().or(patch_mds()).or(put_mds());
+    if let Some(addr_arg) = positionals.first() {
+        addr = addr_arg.clone();
+    }
+    if let Some(snapshot_arg) = positionals.get(1) {
+        snapshot_path = Some(PathBuf::from(snapshot_arg));
+    }
+
+    if let Some(path) = snapshot_path {
+        persistence::restore_from_path(&path);
+        persistence::spawn_snapshot_task(path, Duration::from_secs(snapshot_interval_secs));
+    }
 
-    let arg = match args().nth(1) {
-        Some(arg) => arg,
-        None => "127.0.0.1:8080".to_string(),
-    };
+    let registry = Registry::new();
+    let routes = routes(MMDS.clone()).or(tenant_routes(registry));
+    let routes = with_middleware(routes, &options).recover(handle_rejection);
 
-    match arg.parse::<SocketAddr>() {
-        Ok(addr) => warp::serve(api).run(addr).await,
+    match addr.parse::<SocketAddr>() {
+        Ok(addr) => warp::serve(routes).run(addr).await,
         Err(e) => eprintln!("{}", e.to_string()),
     }
 }